@@ -0,0 +1,123 @@
+//! A multi-producer, multi-consumer injector queue
+//!
+//! Unlike [`wsqueue1::Local`]/[`wsqueue1::Stealer`], whose `push` is
+//! restricted to a single owning thread, an `Injector` may be pushed
+//! into by any number of threads at once. `ThreadPool::execute` uses one
+//! of these for task submission so that submitting work doesn't couple
+//! the caller to any particular worker's queue.
+//!
+//! [`wsqueue1::Local`]: ../wsqueue1/struct.Local.html
+//! [`wsqueue1::Stealer`]: ../wsqueue1/struct.Stealer.html
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use wsqueue1::Local;
+
+/// Largest number of elements [`steal_batch`] will move in one call.
+///
+/// [`steal_batch`]: struct.Injector.html#method.steal_batch
+const MAX_BATCH: usize = 32;
+
+/// An unbounded MPMC queue guarded by a single lock.
+pub struct Injector<T> {
+    queue: Mutex<VecDeque<T>>,
+}
+
+impl<T> Default for Injector<T> {
+    fn default() -> Self {
+        Injector::new()
+    }
+}
+
+impl<T> Injector<T> {
+    /// Creates an empty `Injector`.
+    pub fn new() -> Self {
+        Injector {
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Enqueues an element. May be called by any thread.
+    pub fn push(&self, elem: T) {
+        self.queue.lock().unwrap().push_back(elem);
+    }
+
+    /// Moves up to [`MAX_BATCH`] elements into `dest`'s local queue,
+    /// returning how many were moved.
+    ///
+    /// [`MAX_BATCH`]: constant.MAX_BATCH.html
+    pub fn steal_batch(&self, dest: &Local<T>) -> usize {
+        let mut q = self.queue.lock().unwrap();
+        let n = cmp::min(q.len(), MAX_BATCH);
+        for _ in 0..n {
+            dest.push(q.pop_front().unwrap());
+        }
+        n
+    }
+
+    /// Returns the number of enqueued elements.
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Returns `true` if the queue holds no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Injector;
+    use wsqueue1::Local;
+
+    #[test]
+    fn basics() {
+        let injector = Injector::new();
+
+        assert_eq!(injector.len(), 0);
+        assert!(injector.is_empty());
+
+        injector.push(1);
+        injector.push(2);
+        injector.push(3);
+
+        assert_eq!(injector.len(), 3);
+    }
+
+    #[test]
+    fn steal_batch_drains_into_dest_in_order() {
+        let injector = Injector::new();
+        let (dest, _stealer) = Local::new();
+
+        for i in 0..10 {
+            injector.push(i);
+        }
+
+        let moved = injector.steal_batch(&dest);
+
+        assert_eq!(moved, 10);
+        assert!(injector.is_empty());
+        for i in 0..10 {
+            assert_eq!(dest.pop(), Some(9 - i));
+        }
+    }
+
+    #[test]
+    fn steal_batch_is_capped() {
+        let injector = Injector::new();
+        let (dest, _stealer) = Local::new();
+
+        for i in 0..1000 {
+            injector.push(i);
+        }
+
+        let moved = injector.steal_batch(&dest);
+
+        assert_eq!(moved, super::MAX_BATCH);
+        assert_eq!(dest.len(), super::MAX_BATCH);
+        assert_eq!(injector.len(), 1000 - super::MAX_BATCH);
+    }
+}
@@ -1,4 +1,6 @@
 use std::{thread, time};
+pub mod injector;
+pub mod sync;
 pub mod threadpool;
 pub mod wsqueue1;
 
@@ -6,6 +8,9 @@ pub mod wsqueue1;
 extern crate log;
 use log::{Level, LevelFilter, Metadata, Record};
 
+#[cfg(loom)]
+extern crate loom;
+
 struct SimpleLogger;
 
 impl log::Log for SimpleLogger {
@@ -28,7 +33,11 @@ fn main() {
     log::set_logger(&LOGGER)
         .map(|()| log::set_max_level(LevelFilter::Debug))
         .unwrap();
-    let mut pool = threadpool::ThreadPool::new(4);
+    let mut pool = threadpool::ThreadPool::new(
+        4,
+        threadpool::PollPolicy::default(),
+        threadpool::ShutdownPolicy::default(),
+    );
 
     for i in 0..20 {
         pool.execute(move || {
@@ -0,0 +1,17 @@
+//! Aliases for the synchronization primitives `wsqueue1` builds on.
+//!
+//! In ordinary builds these are just `std::sync`/`std::sync::atomic`.
+//! Under `#[cfg(loom)]` they become `loom`'s shadow implementations
+//! instead, so the same `Inner` code can be run under loom's model
+//! checker to exhaustively explore `push`/`pop`/`steal` interleavings
+//! without touching a single call site.
+
+#[cfg(not(loom))]
+pub use std::sync::atomic::{AtomicIsize, AtomicPtr, Ordering};
+#[cfg(not(loom))]
+pub use std::sync::{Arc, Mutex};
+
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicIsize, AtomicPtr, Ordering};
+#[cfg(loom)]
+pub use loom::sync::{Arc, Mutex};
@@ -6,25 +6,227 @@
 //! according to a number of pending tasks. There is always
 //! at least one thread running but not more that a configured
 //! number.
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 use std::{thread, time};
+
+use injector::Injector;
 use wsqueue1::*;
 
-type Queues<T> = Arc<RwLock<Vec<Mutex<WsQueue<T>>>>>;
+/// Every worker's stealer, shared so that any worker can steal from any
+/// other, and so a new worker's queue becomes visible to everyone as
+/// soon as it's added. A worker's index into this vector is its `id`.
+type Stealers<T> = Arc<RwLock<Vec<Stealer<T>>>>;
 
 const MAX_PENDING_TASKS: usize = 10;
 
+/// Consecutive empty scans a worker spins through before parking.
+const IDLE_SPINS_BEFORE_PARK: usize = 32;
+
+/// Upper bound on how long a parked worker sleeps before waking up to
+/// recheck on its own, as a safety net in case a wake-up is ever missed.
+const PARK_TIMEOUT: time::Duration = time::Duration::from_millis(50);
+
+/// A worker's parking slot. `thread` starts empty and is filled in by
+/// the worker itself once its thread is actually running, since the
+/// `thread::Thread` handle for a not-yet-spawned thread doesn't exist
+/// yet.
+struct SleepSlot {
+    thread: Mutex<Option<thread::Thread>>,
+    sleeping: AtomicBool,
+}
+
+/// Tracks which workers are parked so that newly submitted or stolen
+/// work can wake exactly one of them, instead of every worker busy-
+/// waiting or polling on a timer.
+struct Sleepers {
+    slots: RwLock<Vec<SleepSlot>>,
+    sleeping_count: AtomicUsize,
+}
+
+impl Sleepers {
+    fn new() -> Self {
+        Sleepers {
+            slots: RwLock::new(Vec::new()),
+            sleeping_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserves the next slot, to be filled in by `set_thread` once the
+    /// worker it belongs to actually starts running. Callers must
+    /// reserve in lock-step with `Stealers`, so the returned index
+    /// matches the worker's `id`.
+    fn reserve(&self) -> usize {
+        let mut s = self.slots.write().unwrap();
+        s.push(SleepSlot {
+            thread: Mutex::new(None),
+            sleeping: AtomicBool::new(false),
+        });
+        s.len() - 1
+    }
+
+    /// Fills in the slot reserved for `id` with the calling thread's own
+    /// handle. Must be called by the worker thread itself, before it
+    /// ever calls `mark_sleeping`.
+    fn set_thread(&self, id: usize, thread: thread::Thread) {
+        let s = self.slots.read().unwrap();
+        *s[id].thread.lock().unwrap() = Some(thread);
+    }
+
+    fn mark_sleeping(&self, id: usize) {
+        let s = self.slots.read().unwrap();
+        if !s[id].sleeping.swap(true, Ordering::SeqCst) {
+            self.sleeping_count.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn mark_awake(&self, id: usize) {
+        let s = self.slots.read().unwrap();
+        if s[id].sleeping.swap(false, Ordering::SeqCst) {
+            self.sleeping_count.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Wakes one sleeping worker, if any are currently parked.
+    fn wake_one(&self) {
+        if self.sleeping_count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let s = self.slots.read().unwrap();
+        for slot in s.iter() {
+            if slot.sleeping.swap(false, Ordering::SeqCst) {
+                self.sleeping_count.fetch_sub(1, Ordering::SeqCst);
+                if let Some(ref thread) = *slot.thread.lock().unwrap() {
+                    thread.unpark();
+                }
+                return;
+            }
+        }
+    }
+
+    /// Wakes every currently parked worker. Used for shutdown, where
+    /// every worker needs to notice promptly rather than waiting its
+    /// turn or the `PARK_TIMEOUT` safety net.
+    fn wake_all(&self) {
+        let s = self.slots.read().unwrap();
+        for slot in s.iter() {
+            if slot.sleeping.swap(false, Ordering::SeqCst) {
+                self.sleeping_count.fetch_sub(1, Ordering::SeqCst);
+                if let Some(ref thread) = *slot.thread.lock().unwrap() {
+                    thread.unpark();
+                }
+            }
+        }
+    }
+}
+
+/// How often a worker checks the [`Injector`] for fresh work instead of
+/// draining its own local queue. Without this, a worker that always has
+/// local work could starve tasks submitted through `execute`.
+///
+/// [`Injector`]: ../injector/struct.Injector.html
+#[derive(Clone, Copy)]
+pub enum PollPolicy {
+    /// Poll the injector at most once every `Duration`.
+    Timed(time::Duration),
+    /// Poll the injector once every `n` locally executed jobs.
+    Count(usize),
+}
+
+impl Default for PollPolicy {
+    fn default() -> Self {
+        PollPolicy::Count(100)
+    }
+}
+
+/// Per-worker mutable state tracking when `PollPolicy` is next due.
+enum Fairness {
+    Timed {
+        interval: time::Duration,
+        last_poll: Instant,
+    },
+    Count {
+        every: usize,
+        since_poll: usize,
+    },
+}
+
+impl Fairness {
+    fn new(policy: PollPolicy) -> Self {
+        match policy {
+            PollPolicy::Timed(interval) => Fairness::Timed {
+                interval,
+                last_poll: Instant::now(),
+            },
+            PollPolicy::Count(every) => Fairness::Count {
+                every,
+                since_poll: 0,
+            },
+        }
+    }
+
+    /// Call once per loop iteration. `job_ran` says whether a local job
+    /// was just executed. Returns whether it's time to poll the
+    /// injector.
+    fn poll_due(&mut self, job_ran: bool) -> bool {
+        match *self {
+            Fairness::Timed {
+                interval,
+                ref mut last_poll,
+            } => {
+                if last_poll.elapsed() >= interval {
+                    *last_poll = Instant::now();
+                    true
+                } else {
+                    false
+                }
+            }
+            Fairness::Count {
+                every,
+                ref mut since_poll,
+            } => {
+                if job_ran {
+                    *since_poll += 1;
+                }
+                if *since_poll >= every {
+                    *since_poll = 0;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
 pub enum Task {
     NewJob(Job),
     Terminate,
 }
 
+/// How a pool winds down its workers on shutdown.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownPolicy {
+    /// Let every job already queued ahead of the shutdown signal run to
+    /// completion before a worker stops. This is the default.
+    #[default]
+    Drain,
+    /// Stop each worker as soon as it next checks in, leaving anything
+    /// still queued unrun.
+    Abort,
+}
+
 pub struct ThreadPool {
     max_pending_tasks: usize,
     max_workers: usize,
+    poll_policy: PollPolicy,
+    shutdown_policy: ShutdownPolicy,
     workers: Vec<Worker>,
-    queues: Queues<Task>,
+    injector: Arc<Injector<Task>>,
+    stealers: Stealers<Task>,
+    sleepers: Arc<Sleepers>,
 }
 
 pub trait FnBox {
@@ -40,72 +242,176 @@ impl<F: FnOnce()> FnBox for F {
 type Job = Box<dyn FnBox + Send + 'static>;
 
 impl ThreadPool {
-    /// Creates a new thread pool
-    pub fn new(size: usize) -> ThreadPool {
+    /// Creates a new thread pool, polling the global injector according
+    /// to `poll_policy` and winding down according to `shutdown_policy`
+    /// once dropped.
+    pub fn new(size: usize, poll_policy: PollPolicy, shutdown_policy: ShutdownPolicy) -> ThreadPool {
         assert!(size > 0);
         let mut workers = Vec::with_capacity(size);
-        let arr = Arc::new(RwLock::new(Vec::new()));
+        let injector = Arc::new(Injector::new());
+        let stealers = Arc::new(RwLock::new(Vec::new()));
+        let sleepers = Arc::new(Sleepers::new());
 
         for _ in 0..1 {
-            let mut v = arr.write().unwrap();
-            v.push(Mutex::new(WsQueue::new()));
-        }
-
-        for i in 0..1 {
-            let w = Worker::new(i, &arr.clone());
-            workers.push(w);
+            let (local, stealer) = Local::new();
+            let id = {
+                let mut s = stealers.write().unwrap();
+                s.push(stealer);
+                s.len() - 1
+            };
+            let reserved = sleepers.reserve();
+            debug_assert_eq!(reserved, id);
+            workers.push(Worker::new(
+                id,
+                local,
+                &stealers,
+                &injector,
+                &sleepers,
+                poll_policy,
+            ));
         }
 
         ThreadPool {
             max_pending_tasks: MAX_PENDING_TASKS,
             max_workers: size,
+            poll_policy,
+            shutdown_policy,
             workers,
-            queues: arr.clone(),
+            injector,
+            stealers,
+            sleepers,
+        }
+    }
+
+    /// Replaces any worker whose thread has died, e.g. because a job it
+    /// ran panicked, so the pool stays at its intended size.
+    fn reap_dead_workers(&mut self) {
+        let mut i = 0;
+        while i < self.workers.len() {
+            if !self.workers[i].is_alive() {
+                let dead = self.workers.remove(i);
+                let id = dead.id;
+                warn!("Worker {} died, respawning", id);
+                dead.join();
+
+                let (local, stealer) = Local::new();
+                let old_stealer = {
+                    let mut s = self.stealers.write().unwrap();
+                    mem::replace(&mut s[id], stealer)
+                };
+                // The dead worker's own queue may still hold tasks it
+                // never got to run (including whatever else was queued
+                // alongside the one that panicked); requeue them on the
+                // injector rather than letting them vanish when the old
+                // stealer (and the `Inner` it was the last reference to)
+                // is dropped.
+                while let Some(t) = old_stealer.steal() {
+                    self.injector.push(t);
+                }
+                // Clears any stale sleeping bit left behind if the dead
+                // worker had parked before its thread went away.
+                self.sleepers.mark_awake(id);
+                self.workers.insert(
+                    i,
+                    Worker::new(
+                        id,
+                        local,
+                        &self.stealers,
+                        &self.injector,
+                        &self.sleepers,
+                        self.poll_policy,
+                    ),
+                );
+            }
+            i += 1;
         }
     }
 
-    /// Sends work to the pool
+    /// Sends work to the pool. May be called from any thread.
     pub fn execute<F>(&mut self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        let mut len = 0;
-        {
-            let a = self.queues.read().unwrap();
-
-            for q in a.iter() {
-                let mut lock = q.try_lock();
+        self.reap_dead_workers();
 
-                if let Ok(ref mut mutex) = lock {
-                    len += mutex.len();
-                }
-            }
+        let job = Box::new(f);
+        let len = self.pending_len();
+
+        if len > self.max_pending_tasks && self.workers.len() < self.max_workers {
+            info!("Too many tasks, spawning a new worker!");
+            let (local, stealer) = Local::new();
+            let id = {
+                let mut s = self.stealers.write().unwrap();
+                s.push(stealer);
+                s.len() - 1
+            };
+            let reserved = self.sleepers.reserve();
+            debug_assert_eq!(reserved, id);
+            self.workers.push(Worker::new(
+                id,
+                local,
+                &self.stealers,
+                &self.injector,
+                &self.sleepers,
+                self.poll_policy,
+            ));
         }
 
-        if len > self.max_pending_tasks {
-            let mut a = self.queues.write().unwrap();
+        self.injector.push(Task::NewJob(job));
+        self.sleepers.wake_one();
+    }
 
-            if a.len() < self.max_workers {
-                info!("Too many tasks, spawning a new worker!");
-                a.push(Mutex::new(WsQueue::new()));
+    /// Total jobs sitting in the injector or in any worker's queue,
+    /// whether still there or already stolen but not yet run.
+    fn pending_len(&self) -> usize {
+        let mut len = self.injector.len();
+        let s = self.stealers.read().unwrap();
+        for stealer in s.iter() {
+            len += stealer.len();
+        }
+        len
+    }
 
-                let w = Worker::new(a.len() - 1, &self.queues.clone());
-                self.workers.push(w);
+    /// Winds down every worker according to `shutdown_policy` and joins
+    /// all of their threads. Called automatically on `drop`.
+    fn shutdown(&mut self) {
+        match self.shutdown_policy {
+            ShutdownPolicy::Drain => {
+                // Wait for every job already queued to be picked up
+                // before signaling a stop. We can't just push one
+                // `Terminate` per worker and trust queue order, since a
+                // worker's own queue pops the most recently pushed
+                // element first, so a `Terminate` enqueued after a batch
+                // of jobs could be seen before they are. Reap along the
+                // way so a worker that panics mid-drain doesn't strand
+                // its queue and leave this spinning forever.
+                while self.pending_len() > 0 {
+                    self.reap_dead_workers();
+                    self.sleepers.wake_all();
+                    thread::yield_now();
+                }
+                for _ in 0..self.workers.len() {
+                    self.injector.push(Task::Terminate);
+                }
+            }
+            ShutdownPolicy::Abort => {
+                for worker in &self.workers {
+                    worker.should_stop.store(true, Ordering::SeqCst);
+                }
             }
         }
+        self.sleepers.wake_all();
 
-        {
-            let a = self.queues.read().unwrap();
-            let mut q = a[0].lock().unwrap();
-
-            q.push(Task::NewJob(job));
+        for worker in self.workers.drain(..) {
+            worker.join();
         }
     }
 }
 
 impl Drop for ThreadPool {
-    fn drop(&mut self) {}
+    fn drop(&mut self) {
+        self.shutdown();
+    }
 }
 
 /// A worker that can execute tasks
@@ -113,88 +419,178 @@ impl Drop for ThreadPool {
 /// It loops and tries to receive tasks from it's own
 /// queue or to steal tasks from other queues until it's dropped
 struct Worker {
-    // id is the index of our queue in the Queues vector
+    // id is the index of our own stealer in the Stealers vector, so the
+    // steal loop knows to skip it.
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
-    queues: Queues<Task>,
     should_stop: Arc<AtomicBool>,
+    // Cleared by a guard inside the worker's thread when it returns,
+    // whether that's a clean exit or a panic unwinding out of it, so the
+    // pool can tell a dead worker apart from a merely busy one.
+    alive: Arc<AtomicBool>,
+}
+
+/// Marks a worker's slot dead when dropped, including during an
+/// unwinding panic, since a thread's `Drop`s still run while it unwinds.
+struct AliveGuard(Arc<AtomicBool>);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
 
 impl Worker {
-    fn start(&mut self) {
-        let should_stop = self.should_stop.clone();
-        let queues = self.queues.clone();
-        let id = self.id;
+    /// Creates a new worker, taking ownership of `local` and spawning
+    /// the thread that will drive it.
+    pub fn new(
+        id: usize,
+        local: Local<Task>,
+        stealers: &Stealers<Task>,
+        injector: &Arc<Injector<Task>>,
+        sleepers: &Arc<Sleepers>,
+        poll_policy: PollPolicy,
+    ) -> Worker {
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let thread_should_stop = should_stop.clone();
+        let alive = Arc::new(AtomicBool::new(true));
+        let thread_alive = alive.clone();
+        let stealers = stealers.clone();
+        let injector = injector.clone();
+        let sleepers = sleepers.clone();
 
         let thread = thread::spawn(move || {
-            while !should_stop.load(Ordering::SeqCst) {
-                let qs = queues.read().unwrap();
+            let _alive_guard = AliveGuard(thread_alive);
 
-                debug!("Thread {} qs.len {}", id, qs.len());
+            // The slot at `id` was already reserved by the caller before
+            // spawning, in lock-step with `stealers`; we just need to
+            // fill in our own thread handle before anyone can try to
+            // wake us.
+            sleepers.set_thread(id, thread::current());
 
-                if qs.len() > id {
-                    let mut work;
+            let mut fairness = Fairness::new(poll_policy);
+            let mut idle_spins = 0usize;
 
-                    {
-                        let mut myqueue = qs[id].lock().unwrap();
-                        work = myqueue.pop();
+            while !thread_should_stop.load(Ordering::SeqCst) {
+                let mut work = local.pop();
+
+                if work.is_none() {
+                    debug!("Nothing is on the local queue for thread {}", id);
+
+                    let moved = injector.steal_batch(&local);
+                    if moved > 0 {
+                        debug!("Pulled a batch of {} tasks from the injector!", moved);
+                        work = local.pop();
+                        sleepers.wake_one();
                     }
-                    if work.is_none() {
-                        debug!("Nothing is on the local queue for thread {}", id);
-
-                        for (i, _) in qs.iter().enumerate() {
-                            if i == id {
-                                continue;
-                            }
-                            {
-                                let mut lock = qs[i].try_lock();
-                                if let Ok(ref mut mutex) = lock {
-                                    work = mutex.pop();
-                                } else {
-                                    continue;
-                                }
-                            }
-                            if work.is_some() {
-                                debug!("Have managed to steal work from queue {}!", i);
-                                break;
-                            }
+                }
+
+                if work.is_none() {
+                    let s = stealers.read().unwrap();
+                    for (i, stealer) in s.iter().enumerate() {
+                        if i == id {
+                            continue;
+                        }
+                        let moved = stealer.steal_batch(&local);
+                        if moved > 0 {
+                            debug!(
+                                "Have managed to steal a batch of {} tasks from queue {}!",
+                                moved, i
+                            );
+                            work = local.pop();
+                            sleepers.wake_one();
+                            break;
                         }
                     }
-                    match work {
-                        None => {
-                            debug!("Could not steal from the other queues");
-                            thread::sleep(time::Duration::new(1, 0));
+                }
+
+                if work.is_some() {
+                    idle_spins = 0;
+                } else {
+                    idle_spins += 1;
+                    if idle_spins < IDLE_SPINS_BEFORE_PARK {
+                        thread::yield_now();
+                    } else {
+                        sleepers.mark_sleeping(id);
+
+                        // One more check of our own queue and the
+                        // injector closes the race where work arrived
+                        // there between our scans above and marking
+                        // ourselves asleep: if we find something now, we
+                        // mark ourselves awake again. A `wake_one` that
+                        // comes after that finds us awake and moves on
+                        // to another sleeper; one that comes before it
+                        // still picks us and wakes no one else.
+                        work = local.pop();
+                        if work.is_none() && injector.steal_batch(&local) > 0 {
+                            work = local.pop();
                         }
-                        Some(t) => {
-                            debug!("Got some work!");
-                            match t {
-                                Task::Terminate => {
-                                    debug!("Terminating worker {}", id);
-                                }
-                                Task::NewJob(j) => {
-                                    debug!("Got new job in task {}", id);
-                                    j.call_box();
-                                }
-                            }
+
+                        if work.is_some() {
+                            sleepers.mark_awake(id);
+                        } else {
+                            debug!("Worker {} parking", id);
+                            thread::park_timeout(PARK_TIMEOUT);
+                            sleepers.mark_awake(id);
                         }
+                        idle_spins = 0;
+                    }
+                }
+
+                let mut ran_job = false;
+                let mut terminated = false;
+                if let Some(t) = work {
+                    debug!("Got some work!");
+                    match t {
+                        Task::Terminate => {
+                            debug!("Terminating worker {}", id);
+                            terminated = true;
+                        }
+                        Task::NewJob(j) => {
+                            debug!("Got new job in task {}", id);
+                            j.call_box();
+                            ran_job = true;
+                        }
+                    }
+                }
+
+                if terminated {
+                    break;
+                }
+
+                if fairness.poll_due(ran_job) {
+                    let moved = injector.steal_batch(&local);
+                    if moved > 0 {
+                        debug!("Fairness poll pulled {} tasks from the injector!", moved);
+                        sleepers.wake_one();
                     }
                 }
             }
         });
 
-        self.thread = Some(thread);
+        Worker {
+            id,
+            thread: Some(thread),
+            should_stop,
+            alive,
+        }
     }
 
-    /// Creates a new worker
-    pub fn new(id: usize, q: &Arc<RwLock<Vec<Mutex<WsQueue<Task>>>>>) -> Worker {
-        let mut w = Worker {
-            id,
-            thread: None,
-            queues: Arc::clone(&q),
-            should_stop: Arc::new(AtomicBool::new(false)),
-        };
-        w.start();
-        w
+    /// Whether this worker's thread is still running.
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    /// Waits for this worker's thread to finish on its own — e.g. after
+    /// it's been sent a `Task::Terminate`, or because it already died —
+    /// without forcing an early stop. Logs if the thread had panicked.
+    fn join(mut self) {
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Worker {} panicked while running a job", self.id);
+            }
+            info!("Stopped worker {}", self.id);
+        }
     }
 }
 
@@ -212,46 +608,58 @@ impl Drop for Worker {
 mod test {
     use super::Worker;
     use super::*;
-    use std::sync::{Arc, Mutex, RwLock};
+    use std::sync::{Arc, RwLock};
 
     #[test]
     fn worker_basic_test() {
         let queuenum = 4;
-        let arr = Arc::new(RwLock::new(Vec::new()));
+        let mut locals = Vec::with_capacity(queuenum);
+        let mut stealer_vec = Vec::with_capacity(queuenum);
 
         for _ in 0..queuenum {
-            let mut v = arr.write().unwrap();
-            v.push(Mutex::new(WsQueue::new()));
+            let (local, stealer) = Local::new();
+            locals.push(local);
+            stealer_vec.push(stealer);
+        }
+        let stealers: Stealers<Task> = Arc::new(RwLock::new(stealer_vec));
+        let injector = Arc::new(Injector::new());
+        let sleepers = Arc::new(Sleepers::new());
+        for _ in 0..queuenum {
+            sleepers.reserve();
         }
 
-        let _w = Worker::new(0, &arr.clone());
-
-        for i in 0..queuenum {
-            let a = arr.read().unwrap();
-            let mut q = a[i].lock().unwrap();
-
-            q.push(Task::NewJob(Box::new(move || {
+        let owned_by_worker = locals.remove(0);
+        let _w = Worker::new(
+            0,
+            owned_by_worker,
+            &stealers,
+            &injector,
+            &sleepers,
+            PollPolicy::default(),
+        );
+
+        for (i, local) in locals.into_iter().enumerate() {
+            let i = i + 1;
+            local.push(Task::NewJob(Box::new(move || {
                 println!("new job {}", i);
             })));
         }
 
         thread::sleep(time::Duration::new(10, 0));
 
-        for i in 0..queuenum {
-            let a = arr.read().unwrap();
-            let mut q = a[i].lock().unwrap();
-
-            let res = q.pop();
+        let s = stealers.read().unwrap();
+        for (i, stealer) in s.iter().enumerate() {
+            let res = stealer.steal();
             match res {
                 None => (),
-                Some(_) => panic!("The queues have not been emptied by the worker"),
+                Some(_) => panic!("The queues have not been emptied by the worker {}", i),
             }
         }
     }
 
     #[test]
     fn pool_basic_test() {
-        let mut pool = ThreadPool::new(4);
+        let mut pool = ThreadPool::new(4, PollPolicy::default(), ShutdownPolicy::default());
 
         pool.execute(|| {
             println!("task1");
@@ -263,4 +671,124 @@ mod test {
 
         thread::sleep(time::Duration::new(10, 0));
     }
+
+    #[test]
+    fn pool_with_timed_poll_policy() {
+        let mut pool = ThreadPool::new(
+            2,
+            PollPolicy::Timed(time::Duration::from_millis(1)),
+            ShutdownPolicy::default(),
+        );
+
+        pool.execute(|| {
+            println!("task1");
+        });
+
+        thread::sleep(time::Duration::new(2, 0));
+    }
+
+    #[test]
+    fn pool_drains_pending_jobs_on_drop() {
+        use std::sync::atomic::AtomicUsize;
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        {
+            let mut pool = ThreadPool::new(1, PollPolicy::default(), ShutdownPolicy::Drain);
+
+            for _ in 0..5 {
+                let ran = ran.clone();
+                pool.execute(move || {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+
+        assert_eq!(ran.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn pool_respawns_worker_after_panicking_job() {
+        let mut pool = ThreadPool::new(1, PollPolicy::default(), ShutdownPolicy::default());
+
+        pool.execute(|| {
+            panic!("boom");
+        });
+
+        thread::sleep(time::Duration::new(1, 0));
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran_clone = ran.clone();
+        pool.execute(move || {
+            ran_clone.store(true, Ordering::SeqCst);
+        });
+
+        thread::sleep(time::Duration::new(1, 0));
+
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn pool_requeues_jobs_stranded_by_a_panicking_job() {
+        use std::sync::atomic::AtomicUsize;
+
+        let mut pool = ThreadPool::new(1, PollPolicy::default(), ShutdownPolicy::default());
+
+        // Occupies the only worker so the jobs below pile up in the
+        // injector and get stolen into its local queue together.
+        pool.execute(|| {
+            thread::sleep(time::Duration::new(1, 0));
+        });
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..5 {
+            let ran = ran.clone();
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        // Pushed last, so it ends up on top of the local queue's LIFO
+        // order and runs (and panics) before any of the 5 jobs above.
+        pool.execute(|| {
+            panic!("boom");
+        });
+
+        thread::sleep(time::Duration::new(2, 0));
+
+        // Nudges a reap of the dead worker, which must requeue whatever
+        // was still sitting in its abandoned local queue.
+        pool.execute(|| {});
+        thread::sleep(time::Duration::new(1, 0));
+
+        assert_eq!(ran.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn pool_shutdown_does_not_hang_when_last_worker_is_dead() {
+        use std::sync::atomic::AtomicUsize;
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        {
+            let mut pool = ThreadPool::new(1, PollPolicy::default(), ShutdownPolicy::Drain);
+
+            pool.execute(|| {
+                thread::sleep(time::Duration::new(1, 0));
+            });
+            for _ in 0..3 {
+                let ran = ran.clone();
+                pool.execute(move || {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            pool.execute(|| {
+                panic!("boom");
+            });
+
+            thread::sleep(time::Duration::new(2, 0));
+
+            // The only worker is now dead with jobs still behind it;
+            // dropping the pool from here must not spin forever.
+        }
+
+        assert_eq!(ran.load(Ordering::SeqCst), 3);
+    }
 }
@@ -1,177 +1,574 @@
-//! A work-stealing queue implemented with a double-linked list
+//! A lock-free work-stealing queue
 //!
-//! Heavily based on a safe doubly linked deque from "Learning Rust
-//! With Entirely Too Many Linked Lists.
+//! Implements the Chase-Lev dynamic circular work-stealing deque as
+//! described in Chase & Lev, "Dynamic Circular Work-Stealing Deque"
+//! (SPAA 2005). Ownership of each end is split at the type level: a
+//! [`Local`] handle is created once per owning thread and is the only
+//! thing allowed to [`push`]/[`pop`], while a cloneable [`Stealer`]
+//! handle may be shared freely and only exposes [`steal`].
+//!
+//! [`push`]: struct.Local.html#method.push
+//! [`pop`]: struct.Local.html#method.pop
+//! [`steal`]: struct.Stealer.html#method.steal
+//!
+//! `Inner` is built entirely on top of the [`sync`] aliases rather than
+//! `std::sync` directly, so under `#[cfg(loom)]` it runs on loom's model
+//! checker instead, which exhaustively explores interleavings rather
+//! than relying on chance the way a `thread::sleep`-based test would.
+//! See the `loom_test` module below.
+//!
+//! [`sync`]: ../sync/index.html
+
+use std::cell::{Cell, UnsafeCell};
+use std::cmp;
+use std::marker::PhantomData;
+use std::mem;
+use std::mem::MaybeUninit;
+
+use sync::{Arc, AtomicIsize, AtomicPtr, Mutex, Ordering};
 
-use std::cell::RefCell;
-use std::rc::Rc;
+/// Smallest capacity a buffer is allocated with.
+const MIN_CAP: usize = 32;
 
-/// A double-ended queue implemented with a double-linked list.
+/// Largest number of elements [`steal_batch`] will move in one call.
 ///
-/// [`push`]: #method.push
-/// [`pop`]: #method.pop
-/// [`steal`]: #method.steal
-#[derive(Debug)]
-pub struct WsQueue<T> {
-    head: Link<T>,
-    tail: Link<T>,
-    length: usize,
+/// [`steal_batch`]: struct.Stealer.html#method.steal_batch
+const MAX_BATCH: usize = 32;
+
+/// A power-of-two circular array of slots.
+///
+/// A `Buffer` never runs its own destructor logic for the elements it
+/// holds: ownership of any live element is tracked by the `Inner` that
+/// allocated it, which is the only thing that ever drops a `T` out of one.
+struct Buffer<T> {
+    cap: isize,
+    storage: Box<[UnsafeCell<MaybeUninit<T>>]>,
 }
 
-type Link<T> = Option<Rc<RefCell<Node<T>>>>;
+impl<T> Buffer<T> {
+    fn new(cap: usize) -> Self {
+        debug_assert!(cap.is_power_of_two());
+        let mut storage = Vec::with_capacity(cap);
+        for _ in 0..cap {
+            storage.push(UnsafeCell::new(MaybeUninit::uninit()));
+        }
+        Buffer {
+            cap: cap as isize,
+            storage: storage.into_boxed_slice(),
+        }
+    }
 
-#[derive(Debug)]
-struct Node<T> {
-    elem: T,
-    prev: Link<T>,
-    next: Link<T>,
+    fn mask(&self, index: isize) -> isize {
+        index & (self.cap - 1)
+    }
+
+    /// Reads the slot at `index`, bit-copying it out.
+    ///
+    /// Callers must ensure the slot was previously written and that no
+    /// other reader will treat the same write as live once this returns.
+    unsafe fn read(&self, index: isize) -> T {
+        let slot = &self.storage[self.mask(index) as usize];
+        (*slot.get()).as_ptr().read()
+    }
+
+    unsafe fn write(&self, index: isize, value: T) {
+        let slot = &self.storage[self.mask(index) as usize];
+        (*slot.get()).as_mut_ptr().write(value);
+    }
+
+    /// Allocates a buffer of double the capacity and copies the live
+    /// range `[top, bottom)` into it.
+    unsafe fn grow(&self, bottom: isize, top: isize) -> Box<Buffer<T>> {
+        let grown = Buffer::new((self.cap as usize) * 2);
+        let mut i = top;
+        while i < bottom {
+            grown.write(i, self.read(i));
+            i += 1;
+        }
+        Box::new(grown)
+    }
 }
 
-impl<T> Node<T> {
-    fn new(elem: T) -> Rc<RefCell<Node<T>>> {
-        Rc::new(RefCell::new(Node {
-            elem: elem,
-            prev: None,
-            next: None,
-        }))
+/// The state shared between a queue's `Local` and its `Stealer`s.
+struct Inner<T> {
+    bottom: AtomicIsize,
+    top: AtomicIsize,
+    buffer: AtomicPtr<Buffer<T>>,
+    /// Buffers that have been grown out of. A thief may still be
+    /// mid-read from one of these when the owner replaces it, so they
+    /// can't be freed immediately; they live here until the `Inner`
+    /// itself is dropped.
+    ///
+    /// This is per-queue, not a pool shared across queues: nothing ever
+    /// pulls a buffer back out of it for reuse, so it only ever grows
+    /// until the owning `Inner` (and thus every retired buffer in it)
+    /// drops. Don't assume a later caller can borrow from this.
+    retired: Mutex<Vec<Buffer<T>>>,
+}
+
+impl<T> Inner<T> {
+    fn new() -> Self {
+        let buffer = Box::new(Buffer::new(MIN_CAP));
+        Inner {
+            bottom: AtomicIsize::new(0),
+            top: AtomicIsize::new(0),
+            buffer: AtomicPtr::new(Box::into_raw(buffer)),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn push(&self, elem: T) {
+        let b = self.bottom.load(Ordering::SeqCst);
+        let t = self.top.load(Ordering::SeqCst);
+
+        let mut buf = unsafe { &*self.buffer.load(Ordering::SeqCst) };
+        if b - t >= buf.cap - 1 {
+            let grown = unsafe { buf.grow(b, t) };
+            let old = self.buffer.swap(Box::into_raw(grown), Ordering::SeqCst);
+            self.retired
+                .lock()
+                .unwrap()
+                .push(*unsafe { Box::from_raw(old) });
+            buf = unsafe { &*self.buffer.load(Ordering::SeqCst) };
+        }
+
+        unsafe { buf.write(b, elem) };
+        self.bottom.store(b + 1, Ordering::SeqCst);
+    }
+
+    fn pop(&self) -> Option<T> {
+        let b = self.bottom.load(Ordering::SeqCst) - 1;
+        let buf = unsafe { &*self.buffer.load(Ordering::SeqCst) };
+        self.bottom.store(b, Ordering::SeqCst);
+        let t = self.top.load(Ordering::SeqCst);
+
+        if t > b {
+            // Queue was already empty; restore bottom.
+            self.bottom.store(b + 1, Ordering::SeqCst);
+            return None;
+        }
+
+        let elem = unsafe { buf.read(b) };
+        if t == b {
+            // This was the last element: race a thief for it. Don't
+            // treat `elem` as ours until the CAS actually wins it,
+            // since a concurrent `steal`/`steal_batch` may already be
+            // holding its own bit-copy of the same slot.
+            let result = if self
+                .top
+                .compare_exchange(t, t + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                Some(elem)
+            } else {
+                // Lost the race: the thief's bit-copy is the real one.
+                mem::forget(elem);
+                None
+            };
+            self.bottom.store(b + 1, Ordering::SeqCst);
+            result
+        } else {
+            Some(elem)
+        }
+    }
+
+    fn steal(&self) -> Option<T> {
+        let t = self.top.load(Ordering::SeqCst);
+        let b = self.bottom.load(Ordering::SeqCst);
+
+        if t >= b {
+            return None;
+        }
+
+        let buf = unsafe { &*self.buffer.load(Ordering::SeqCst) };
+        let elem = unsafe { buf.read(t) };
+
+        if self
+            .top
+            .compare_exchange(t, t + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            Some(elem)
+        } else {
+            // Lost the race: someone else already took this slot, so our
+            // bit-copy isn't really ours to drop.
+            mem::forget(elem);
+            None
+        }
+    }
+
+    fn steal_batch(&self, dest: &Inner<T>) -> usize {
+        let t = self.top.load(Ordering::SeqCst);
+        let b = self.bottom.load(Ordering::SeqCst);
+
+        let len = b - t;
+        if len <= 0 {
+            return 0;
+        }
+
+        let n = cmp::min(cmp::max(len / 2, 1), MAX_BATCH as isize);
+        let buf = unsafe { &*self.buffer.load(Ordering::SeqCst) };
+
+        let mut items = Vec::with_capacity(n as usize);
+        for i in 0..n {
+            items.push(unsafe { buf.read(t + i) });
+        }
+
+        if self
+            .top
+            .compare_exchange(t, t + n, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            let moved = items.len();
+            for item in items {
+                dest.push(item);
+            }
+            moved
+        } else {
+            // Lost the race: none of these slots are ours.
+            for item in items {
+                mem::forget(item);
+            }
+            0
+        }
+    }
+
+    fn len(&self) -> usize {
+        let b = self.bottom.load(Ordering::SeqCst);
+        let t = self.top.load(Ordering::SeqCst);
+        if b > t {
+            (b - t) as usize
+        } else {
+            0
+        }
     }
 }
 
-unsafe impl<T: Send> Send for WsQueue<T> {}
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // `&mut self` already guarantees exclusive access here, but we
+        // still go through the ordinary atomic ops (rather than e.g.
+        // `get_mut`) so this keeps working under loom, which doesn't
+        // expose those shortcuts.
+        let b = self.bottom.load(Ordering::SeqCst);
+        let t = self.top.load(Ordering::SeqCst);
+        let buf = unsafe { Box::from_raw(self.buffer.load(Ordering::SeqCst)) };
+        let mut i = t;
+        while i < b {
+            unsafe { drop(buf.read(i)) };
+            i += 1;
+        }
+    }
+}
+
+/// The owning handle of a work-stealing queue.
+///
+/// Created once per owning thread by [`Local::new`]; only this handle may
+/// `push` or `pop`. It is `Send` (so it can be moved into the thread that
+/// will own it) but not `Sync`, since the Chase-Lev algorithm requires
+/// `push`/`pop` to never run concurrently with each other.
+///
+/// [`Local::new`]: #method.new
+pub struct Local<T> {
+    inner: Arc<Inner<T>>,
+    // Inner<T> is actually Sync, so this marker is what makes `Local`
+    // `!Sync` while staying `Send`.
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// A cloneable handle that may only `steal` from a work-stealing queue.
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
 
-impl<T> WsQueue<T> {
-    /// Creates an empty `WsQueue`.
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Stealer {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Local<T> {
+    /// Creates an empty work-stealing queue, returning its owning
+    /// [`Local`] handle and a [`Stealer`] that may be cloned and shared
+    /// with any number of thief threads.
     ///
     /// # Examples
     ///
     /// ```
-    /// let wsq: WsQueue<i32> = WsQueue::new();
+    /// let (local, stealer): (Local<i32>, Stealer<i32>) = Local::new();
     /// ```
-    pub fn new() -> Self {
-        WsQueue {
-            head: None,
-            tail: None,
-            length: 0,
-        }
+    pub fn new() -> (Local<T>, Stealer<T>) {
+        let inner = Arc::new(Inner::new());
+        (
+            Local {
+                inner: inner.clone(),
+                _not_sync: PhantomData,
+            },
+            Stealer { inner },
+        )
     }
 
-    /// Enqueues an element
+    /// Enqueues an element.
     ///
     /// # Examples
     /// ```
-    /// let mut wsq = WsQueue::new();
+    /// let (local, _stealer) = Local::new();
     ///
-    /// wsq.push(1);
+    /// local.push(1);
     /// ```
-    pub fn push(&mut self, elem: T) {
-        let new_head = Node::new(elem);
-        match self.head.take() {
-            Some(old_head) => {
-                old_head.borrow_mut().prev = Some(new_head.clone());
-                new_head.borrow_mut().next = Some(old_head);
-                self.head = Some(new_head);
-            }
-            None => {
-                self.tail = Some(new_head.clone());
-                self.head = Some(new_head);
-            }
-        }
-        self.length += 1;
+    pub fn push(&self, elem: T) {
+        self.inner.push(elem)
     }
 
-    /// Steals an element from the beginning of the queue
+    /// Dequeues the element most recently pushed.
     ///
     /// # Examples
+    ///
     /// ```
-    /// let mut wsq = WsQueue::new();
+    /// let (local, _stealer) = Local::new();
     ///
-    /// wsq.push(1);
-    /// wsq.push(2);
-    /// wsq.push(3);
+    /// local.push(1);
+    /// local.push(2);
+    /// local.push(3);
     ///
-    /// assert_eq!(wsq.steal(), Some(3));
+    /// assert_eq!(local.pop(), Some(3));
     /// ```
-    pub fn steal(&mut self) -> Option<T> {
-        self.head.take().map(|old_head| {
-            match old_head.borrow_mut().next.take() {
-                Some(new_head) => {
-                    new_head.borrow_mut().prev.take();
-                    self.head = Some(new_head);
-                }
-                None => {
-                    self.tail.take();
-                }
-            }
-            self.length -= 1;
-            Rc::try_unwrap(old_head).ok().unwrap().into_inner().elem
-        })
+    pub fn pop(&self) -> Option<T> {
+        self.inner.pop()
     }
 
-    /// Dequeues the element from the end of the queue
+    /// Returns the number of enqueued elements.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns `true` if the queue holds no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Stealer<T> {
+    /// Steals an element from the opposite end of the queue. May be
+    /// called concurrently by any number of thief threads.
     ///
     /// # Examples
-    ///
     /// ```
-    /// let mut wsq = WsQueue::new();
+    /// let (local, stealer) = Local::new();
     ///
-    /// wsq.push(1);
-    /// wsq.push(2);
-    /// wsq.push(3);
+    /// local.push(1);
+    /// local.push(2);
+    /// local.push(3);
     ///
-    /// assert_eq!(wsq.pop(), Some(1));
+    /// assert_eq!(stealer.steal(), Some(1));
     /// ```
-    pub fn pop(&mut self) -> Option<T> {
-        self.tail.take().map(|old_tail| {
-            match old_tail.borrow_mut().prev.take() {
-                Some(new_tail) => {
-                    new_tail.borrow_mut().next.take();
-                    self.tail = Some(new_tail);
-                }
-                None => {
-                    self.head.take();
-                }
-            }
-            self.length -= 1;
-            Rc::try_unwrap(old_tail).ok().unwrap().into_inner().elem
-        })
+    pub fn steal(&self) -> Option<T> {
+        self.inner.steal()
     }
 
-    /// Returns the number of enqueued elements
+    /// Steals roughly half of the victim's elements (capped at
+    /// [`MAX_BATCH`]) into `dest`'s owning queue in a single
+    /// synchronization round, so a thief need not pay the cost of a CAS
+    /// per task. Returns the number of elements moved; `dest` still holds
+    /// them afterwards, so the caller should `pop` its own share from
+    /// `dest` rather than stealing it back out.
     ///
-    /// # Examples
-    /// ```
-    /// let mut wsq = WsQueue::new();
-    ///
-    /// wsq.push(1);
-    /// wsq.push(2);
-    /// wsq.push(3);
+    /// May be called concurrently by any number of thief threads, the
+    /// same as [`steal`].
     ///
-    /// assert_eq!(wsq.len(), 3);
-    /// ```
+    /// [`MAX_BATCH`]: constant.MAX_BATCH.html
+    /// [`steal`]: #method.steal
+    pub fn steal_batch(&self, dest: &Local<T>) -> usize {
+        self.inner.steal_batch(&dest.inner)
+    }
+
+    /// Returns the number of enqueued elements.
     pub fn len(&self) -> usize {
-        self.length
+        self.inner.len()
+    }
+
+    /// Returns `true` if the queue holds no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod test {
-    use super::WsQueue;
+    use super::Local;
 
     #[test]
     fn basics() {
-        let mut list = WsQueue::new();
+        let (local, stealer) = Local::new();
+
+        assert_eq!(local.pop(), None);
+        assert_eq!(local.len(), 0);
+
+        local.push(1);
+        local.push(2);
+        local.push(3);
+
+        assert_eq!(local.len(), 3);
+
+        assert_eq!(local.pop(), Some(3));
+        assert_eq!(stealer.steal(), Some(1));
+        assert_eq!(local.len(), 1);
+    }
+
+    #[test]
+    fn grows_past_initial_capacity() {
+        let (local, _stealer) = Local::new();
+
+        for i in 0..1000 {
+            local.push(i);
+        }
+        assert_eq!(local.len(), 1000);
+
+        for i in (0..1000).rev() {
+            assert_eq!(local.pop(), Some(i));
+        }
+        assert_eq!(local.pop(), None);
+    }
+
+    #[test]
+    fn steal_batch_moves_roughly_half() {
+        let (victim, victim_stealer) = Local::new();
+        let (thief, _thief_stealer) = Local::new();
+
+        for i in 0..10 {
+            victim.push(i);
+        }
+
+        let moved = victim_stealer.steal_batch(&thief);
+
+        assert_eq!(moved, 5);
+        assert_eq!(victim.len(), 5);
+        assert_eq!(thief.len(), 5);
+
+        for i in 0..5 {
+            assert_eq!(thief.pop(), Some(4 - i));
+        }
+        assert_eq!(thief.pop(), None);
+    }
+
+    #[test]
+    fn steal_batch_is_capped() {
+        let (victim, victim_stealer) = Local::new();
+        let (thief, _thief_stealer) = Local::new();
+
+        for i in 0..1000 {
+            victim.push(i);
+        }
+
+        let moved = victim_stealer.steal_batch(&thief);
+
+        assert_eq!(moved, super::MAX_BATCH);
+        assert_eq!(thief.len(), super::MAX_BATCH);
+    }
+
+    #[test]
+    fn stealer_can_be_cloned_and_shared() {
+        let (local, stealer) = Local::new();
+
+        local.push(1);
+
+        let other = stealer.clone();
+        let t = ::std::thread::spawn(move || other.steal());
+
+        let mut results = vec![stealer.steal(), t.join().unwrap()];
+        results.retain(Option::is_some);
+        assert_eq!(results, vec![Some(1)]);
+    }
+}
+
+/// Model tests run under loom's exhaustive scheduler rather than a real
+/// OS thread scheduler, so they need their own build of the crate with
+/// `RUSTFLAGS="--cfg loom" cargo test --release`. The ordinary `test`
+/// module above is gated `not(loom)`, so of this file's tests a loom build
+/// only ever compiles and runs these, regardless of how `cargo test` is
+/// invoked. The test modules of `injector` and `threadpool` are not gated
+/// on `loom` and are compiled in a loom build as well.
+#[cfg(all(test, loom))]
+mod loom_test {
+    use super::Local;
+
+    #[test]
+    fn push_pop_races_with_concurrent_steals() {
+        const N: usize = 3;
+
+        loom::model(|| {
+            let (local, stealer) = Local::new();
+            for i in 0..N {
+                local.push(i);
+            }
+
+            let other = stealer.clone();
+            let t = loom::thread::spawn(move || other.steal());
+
+            let mut got = Vec::new();
+            if let Some(v) = local.pop() {
+                got.push(v);
+            }
+            if let Some(v) = stealer.steal() {
+                got.push(v);
+            }
+            if let Some(v) = t.join().unwrap() {
+                got.push(v);
+            }
+
+            // A single, non-retrying attempt can legitimately lose a
+            // race and come back empty, so not every element need be
+            // claimed here. What must never happen is the same element
+            // being handed out twice, or a value that was never pushed.
+            let mut seen = got.clone();
+            seen.sort();
+            seen.dedup();
+            assert_eq!(seen.len(), got.len(), "an element was handed out twice");
+            assert!(got.iter().all(|v| *v < N), "an unpushed value was produced");
+        });
+    }
+
+    #[test]
+    fn pop_races_concurrent_steal_for_last_element() {
+        use std::sync::atomic::{AtomicUsize, Ordering as StdOrdering};
+        use std::sync::Arc as StdArc;
+
+        struct DropCounter(StdArc<AtomicUsize>);
+
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, StdOrdering::SeqCst);
+            }
+        }
+
+        loom::model(|| {
+            let drops = StdArc::new(AtomicUsize::new(0));
+            let (local, stealer) = Local::new();
+            local.push(DropCounter(drops.clone()));
+
+            let t = loom::thread::spawn(move || stealer.steal());
 
-        assert_eq!(list.pop(), None);
-        assert_eq!(list.len(), 0);
+            let popped = local.pop();
+            let stolen = t.join().unwrap();
 
-        list.push(1);
-        list.push(2);
-        list.push(3);
+            // Exactly one side can win the single element in the
+            // queue; the other must see `None`, not its own
+            // independent bit-copy of the same value.
+            assert!(
+                popped.is_some() ^ stolen.is_some(),
+                "pop and steal both claimed the same element"
+            );
 
-        assert_eq!(list.len(), 3);
+            drop(popped);
+            drop(stolen);
 
-        assert_eq!(list.pop(), Some(1));
-        assert_eq!(list.pop(), Some(2));
-        assert_eq!(list.len(), 1);
+            assert_eq!(
+                drops.load(StdOrdering::SeqCst),
+                1,
+                "element was not dropped exactly once"
+            );
+        });
     }
 }